@@ -1,12 +1,35 @@
-use chrono::Local;
-use clap::Parser;
+use batch_analyzer::{
+    add_cookie_header, check_chrome, check_lighthouse, check_network, check_node, check_writable,
+    collision_free_hash_length, crawl, dedup_urls, expand_template, fetch_text, find_executable,
+    find_runs, format_checklist, is_http_url, is_local_path, is_locale, lighthouse_version,
+    local_url, locate_run, mask_credentials, mask_headers, normalize_url, parse_headers,
+    proxy_chrome_flag, read_cookies_file, read_csv_urls, read_failures, read_lines,
+    read_remote_lines, read_url_config, read_url_groups, run_batch, sanitize_name, sitemap_urls,
+    split_flags, strip_comment, summarize_run, take_credentials, template_url_count, write_archive,
+    ArtifactMode, AuditOptions, BatchConfig, CancelToken, Category, ChromeChannel, Concurrency,
+    ExitCode, FilenameStyle, FormFactor, KeepRun, LighthouseConfig, OutputFormat, RetryBudget,
+    RobotsCache, Shard, StaticServer, Thresholds, Throttling, UrlFilter, UrlFunnel, UrlOverrides,
+    UrlStatus, DEFAULT_CHROME_FLAGS, DEFAULT_HASH_LENGTH, MAX_CONCURRENCY, SUMMARY_LOG_TARGET,
+};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 use dotenv::dotenv;
-use sha2::{Digest, Sha256};
+use log::{error, info, warn, LevelFilter};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
-use std::fs::{self, File};
-use std::io::{self, BufRead};
-use std::path::Path;
-use std::process::Command;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+/// Most URLs one `--expand-templates` line may produce, so a typo in a range can't queue up
+/// days of audits.
+const MAX_TEMPLATE_URLS: usize = 10_000;
 
 /// A simple CLI to run Lighthouse on a list of URLs from a file.
 #[derive(Parser, Debug)]
@@ -16,385 +39,2092 @@ struct Args {
     #[arg(short, long)]
     name: Option<String>,
 
-    /// The path to the file containing URLs, one per line.
+    /// The path to the file containing URLs, one per line. Use `-` to read from standard input.
+    /// Files ending in `.csv` are read as CSV with a header row. An http(s) URL downloads the
+    /// list, one URL per line, from there.
     #[arg(short, long, default_value = "urls.txt")]
     file: String,
 
+    /// CSV column holding the URLs (default: `url`). Setting it reads --file as CSV whatever
+    /// its extension. An optional `label` column names each page in the report index.
+    #[arg(long)]
+    url_column: Option<String>,
+
+    /// Only audit these groups of a YAML --file (comma-separated or repeated).
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    group: Vec<String>,
+
+    /// Read URLs from standard input instead of a file. Same as `--file -`.
+    #[arg(long, conflicts_with = "file")]
+    stdin: bool,
+
+    /// Serve this directory over HTTP on 127.0.0.1 for the run, and audit the local files in
+    /// the URL list (`./dist/index.html`, `file:///...`) through it instead of as `file://`
+    /// URLs. The files must be inside this directory.
+    #[arg(long, value_name = "DIR")]
+    serve_dir: Option<String>,
+
+    /// Port for --serve-dir, so report names and baselines stay the same between runs. Any
+    /// free port when 0.
+    #[arg(long, default_value_t = 0, requires = "serve_dir")]
+    serve_port: u16,
+
+    /// Expand bash-style brace templates in the URLs file, e.g. `https://example.com/{en,de}/`
+    /// or `https://example.com/product/{1..100}`, into one URL per combination.
+    #[arg(long)]
+    expand_templates: bool,
+
     /// Directory where report folders will be created.
     #[arg(short, long, default_value = "reports")]
     reports_dir: String,
+
+    /// Number of URLs to analyze in parallel. Each parallel run launches its own
+    /// headless Chrome instance, so this is also the maximum number of Chrome
+    /// processes alive at once. Capped at 8. `auto` uses half the available CPUs.
+    #[arg(short, long, default_value_t = Concurrency::Fixed(1))]
+    concurrency: Concurrency,
+
+    /// Report format(s) Lighthouse should write for each URL.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Html)]
+    format: OutputFormat,
+
+    /// Device to emulate. Desktop runs use Lighthouse's desktop preset (no mobile throttling).
+    /// A comma-separated list, e.g. `mobile,desktop`, audits every URL once per form factor.
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "mobile")]
+    form_factor: Vec<FormFactor>,
+
+    /// Number of times to re-run Lighthouse for a URL after a failure, waiting 1s, 2s, 4s, ...
+    /// between attempts.
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+
+    /// Cap on retries across the whole batch. Once it's used up, failures aren't retried any
+    /// more, on the assumption that something is broken for every URL.
+    #[arg(long, value_name = "N")]
+    max_retries_total: Option<u32>,
+
+    /// Maximum number of seconds a single Lighthouse run may take before it (and the Chrome it
+    /// started) is killed and the URL is recorded as timed out.
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    /// Time budget for the whole batch in seconds. Once it runs out, URLs already being
+    /// audited finish and the rest are recorded as not analyzed.
+    #[arg(long)]
+    max_duration: Option<u64>,
+
+    /// Stop the batch at the first URL that fails. Audits already running finish, the rest are
+    /// recorded as not analyzed, and the run exits with status 2.
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Count a URL as failed when Lighthouse finished but reported a runtime error, run
+    /// warnings or a category without a score, instead of "succeeded with warnings".
+    #[arg(long)]
+    fail_on_warnings: bool,
+
+    /// Before the batch, follow each URL's redirects with a HEAD request and warn about the ones
+    /// that land on a different URL, since Lighthouse audits the final page. Both URLs are
+    /// recorded in the manifest.
+    #[arg(long)]
+    check_redirects: bool,
+
+    /// Audit the URL a redirecting URL lands on instead of the URL itself.
+    #[arg(long, requires = "check_redirects")]
+    audit_final_url: bool,
+
+    /// Before the batch, send a quick request to every URL and report the ones that fail DNS,
+    /// refuse the connection, time out or answer with a 4xx/5xx status.
+    #[arg(long)]
+    preflight: bool,
+
+    /// Don't audit the URLs --preflight found unreachable; they are recorded as failed.
+    #[arg(long, requires = "preflight")]
+    skip_unreachable: bool,
+
+    /// Minimum milliseconds between the starts of any two audits, across all workers.
+    #[arg(long, default_value_t = 0)]
+    delay: u64,
+
+    /// Minimum milliseconds between the starts of two audits of URLs on the same host, across
+    /// all workers. Audits of other hosts aren't held back.
+    #[arg(long, default_value_t = 0)]
+    per_host_delay: u64,
+
+    /// Before starting each audit, wait while system memory use is above this percentage
+    /// and audits are still running. A heuristic that lets --concurrency back off on small
+    /// machines; needs the `memory` feature.
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u32).range(1..=100))]
+    max_memory_percent: Option<u32>,
+
+    /// Write reports into this exact directory, creating it if needed, instead of a new
+    /// timestamped folder under --reports-dir. Can be set via BATCH_ANALYZER_OUTPUT_DIR. Point it
+    /// at an existing run folder together with --resume to continue it.
+    #[arg(long)]
+    output_dir: Option<String>,
+
+    /// Write reports straight into --reports-dir instead of a timestamped folder, with the run
+    /// name and timestamp at the start of every file name (`name_timestamp_report_<hash>.html`,
+    /// `name_timestamp_index.html`, ...) so runs don't overwrite each other.
+    #[arg(long, alias = "no-timestamp", conflicts_with_all = ["output_dir", "resume", "s3_bucket", "archive", "archive_only"])]
+    flat: bool,
+
+    /// Bundle the run folder into `<folder>.tar.gz` next to it once the run is done.
+    #[arg(long)]
+    archive: bool,
+
+    /// Like --archive, then remove the run folder so only the archive is left.
+    #[arg(long, conflicts_with = "open_summary")]
+    archive_only: bool,
+
+    /// chrono strftime format for the timestamp in run folder and file names, e.g.
+    /// `%Y-%m-%dT%H%M%S`. The result must be usable as a file name, so `/`, `\` and `:` are
+    /// rejected.
+    #[arg(long, default_value = DEFAULT_TIMESTAMP_FORMAT)]
+    timestamp_format: String,
+
+    /// Take the timestamp in UTC instead of local time.
+    #[arg(long)]
+    utc: bool,
+
+    /// Skip URLs whose report already exists in the output directory.
+    #[arg(long)]
+    resume: bool,
+
+    /// Don't save each URL's Lighthouse output to `logs/<hash>.log` in the output directory.
+    #[arg(long)]
+    no_logs: bool,
+
+    /// Print the Lighthouse command and report path for every URL without running anything.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Abort the run if any line in the URLs file is not a valid http(s) URL, instead of
+    /// skipping it.
+    #[arg(long)]
+    strict: bool,
+
+    /// Exit successfully with a warning when no valid URLs were read, instead of failing.
+    #[arg(long)]
+    continue_on_empty_file: bool,
+
+    /// Audit a URL every time it is listed instead of dropping repeats. Repeats share a
+    /// report file name, so each run overwrites the previous report.
+    #[arg(long)]
+    allow_duplicates: bool,
+
+    /// Path or name of the Lighthouse executable. Can be set via BATCH_ANALYZER_LIGHTHOUSE_BIN
+    /// environment variable. Defaults to `lighthouse` on the PATH.
+    #[arg(long)]
+    lighthouse_bin: Option<String>,
+
+    /// Chrome executable for Lighthouse to launch, passed to it as `CHROME_PATH`. Can be set via
+    /// BATCH_ANALYZER_CHROME_PATH environment variable. Defaults to Lighthouse's own search.
+    #[arg(long)]
+    chrome_path: Option<String>,
+
+    /// Audit with this Chrome release channel, found in its standard install location and
+    /// passed to Lighthouse as `CHROME_PATH`. The channel and its version are recorded in the
+    /// manifest.
+    #[arg(long, value_enum, conflicts_with = "chrome_path")]
+    chrome_channel: Option<ChromeChannel>,
+
+    /// Minimum performance score (0-100). Any URL below it makes the run exit non-zero.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=100))]
+    min_performance: Option<u32>,
+
+    /// Minimum accessibility score (0-100). Any URL below it makes the run exit non-zero.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=100))]
+    min_accessibility: Option<u32>,
+
+    /// Minimum SEO score (0-100). Any URL below it makes the run exit non-zero.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=100))]
+    min_seo: Option<u32>,
+
+    /// Minimum best-practices score (0-100). Any URL below it makes the run exit non-zero.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=100))]
+    min_best_practices: Option<u32>,
+
+    /// Lighthouse budget.json passed to every run as `--budget-path`. Resources and timings
+    /// over budget are listed at the end of the run.
+    #[arg(long)]
+    budget: Option<String>,
+
+    /// Custom Lighthouse config passed to every run as `--config-path`, e.g. one that skips
+    /// audits. Its path and a hash of its contents are recorded in the manifest.
+    #[arg(long, value_name = "PATH")]
+    lh_config: Option<String>,
+
+    /// Exit non-zero when any URL exceeds the --budget.
+    #[arg(long, requires = "budget")]
+    fail_on_budget: bool,
+
+    /// Comma-separated list of Lighthouse categories to run, e.g. `performance,seo`.
+    /// All categories run when omitted.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    only_categories: Vec<Category>,
+
+    /// Space-separated Chrome flags that REPLACE the defaults (`--headless --no-sandbox
+    /// --disable-cache`). This drops the default sandbox and headless settings unless you repeat
+    /// them, so only use it when you know what Chrome should run with. Quote values containing
+    /// spaces, e.g. `--user-data-dir="/tmp/my profile"`.
+    #[arg(long, allow_hyphen_values = true)]
+    chrome_flags: Option<String>,
+
+    /// Space-separated Chrome flags appended to the defaults (or to --chrome-flags),
+    /// e.g. `--disable-gpu`.
+    #[arg(long, allow_hyphen_values = true)]
+    extra_chrome_flags: Option<String>,
+
+    /// Route Chrome's traffic through this proxy, e.g. `http://proxy.corp:3128` or
+    /// `socks5://127.0.0.1:1080`. Added as `--proxy-server` on top of the default Chrome flags
+    /// or --chrome-flags, and wins over a `--proxy-server` given in either.
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Keep the browser cache instead of cold-loading every page: drops `--disable-cache` from
+    /// the Chrome flags and tells Lighthouse not to clear storage. Warm-cache scores measure a
+    /// repeat visit and aren't comparable with the default cold-cache ones.
+    #[arg(long)]
+    keep_cache: bool,
+
+    /// Chrome profile directory to reuse, so the cache --keep-cache keeps survives between
+    /// runs of the same batch. Chrome can't share a profile, so this needs --concurrency 1.
+    #[arg(long, value_name = "DIR")]
+    user_data_dir: Option<String>,
+
+    /// Extra HTTP header sent with every request, as `KEY=VALUE`. Repeat for more headers,
+    /// e.g. `--header "Authorization=Basic dXNlcjpwYXNz" --header X-Env=staging`.
+    #[arg(long = "header", value_name = "KEY=VALUE")]
+    headers: Vec<String>,
+
+    /// Cookie sent with every request, as `NAME=VALUE`. Repeat for more cookies; they are
+    /// combined into one `Cookie` header together with any from --header.
+    #[arg(long = "cookie", value_name = "NAME=VALUE")]
+    cookies: Vec<String>,
+
+    /// File of cookies to send, one `NAME=VALUE` per line or a Netscape `cookies.txt` export.
+    #[arg(long)]
+    cookies_file: Option<String>,
+
+    /// Network/CPU throttling profile. Lighthouse's own default applies when omitted. Scores are
+    /// only comparable between runs using the same profile.
+    #[arg(long, value_enum)]
+    throttling: Option<Throttling>,
+
+    /// Locale for Lighthouse's report and emulation, e.g. `de` or `pt-BR`. Lighthouse's own
+    /// default applies when omitted. Recorded in the manifest, as scores are only comparable
+    /// between runs in the same locale.
+    #[arg(long)]
+    locale: Option<String>,
+
+    /// User agent string the audits identify with instead of Lighthouse's default, e.g. to
+    /// get the content real browsers get from sites that treat Lighthouse differently.
+    /// Recorded in the manifest.
+    #[arg(long, value_name = "STRING")]
+    user_agent: Option<String>,
+
+    /// Also send `Accept-Language: <locale>` with every request so the page serves its
+    /// localized content. A `--header Accept-Language=...` wins over it.
+    #[arg(long, requires = "locale")]
+    accept_language: bool,
+
+    /// Lighthouse plugin to load in every audit, e.g. `lighthouse-plugin-field-performance`.
+    /// It must be installed where Lighthouse can require it. Repeat for more. Recorded in the
+    /// manifest, as plugins add their own categories to the reports.
+    #[arg(long = "plugin", value_name = "NAME")]
+    plugins: Vec<String>,
+
+    /// Audit each URL this many times and report the median score of each category.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    runs: u32,
+
+    /// Run Lighthouse once per URL before the measured run(s) to prime caches and CDNs. The
+    /// warmup report is thrown away.
+    #[arg(long)]
+    warmup: bool,
+
+    /// Which run's report to keep when --runs is greater than 1.
+    #[arg(long, value_enum, default_value_t = KeepRun::Median)]
+    keep_run: KeepRun,
+
+    /// Only load each page and save the artifacts Lighthouse gathers under `artifacts/<hash>/`
+    /// in the output directory, without auditing them (Lighthouse's `-G`). Audit them later,
+    /// offline, with --audit-only.
+    #[arg(long, conflicts_with = "audit_only")]
+    gather_only: bool,
+
+    /// Audit from the artifacts a --gather-only run saved in RUN_DIR instead of loading the
+    /// pages, so no network is needed (Lighthouse's `-A`).
+    #[arg(long, value_name = "RUN_DIR")]
+    audit_only: Option<String>,
+
+    /// How report files are named.
+    #[arg(long, value_enum, default_value_t = FilenameStyle::Hash)]
+    filename_style: FilenameStyle,
+
+    /// Hash characters in `--filename-style hash` report names and in log names (8-64).
+    /// Lengthened automatically if two URLs in the batch would share a name.
+    #[arg(long, default_value_t = DEFAULT_HASH_LENGTH as u64, value_parser = clap::value_parser!(u64).range(8..=64))]
+    hash_length: u64,
+
+    /// Gzip each report to `<name>.html.gz` / `<name>.json.gz`, removing the uncompressed file.
+    #[arg(long)]
+    compress: bool,
+
+    /// Open each report in the browser once Lighthouse finishes. Off by default so unattended
+    /// and headless runs don't try to launch a browser.
+    #[arg(long)]
+    view: bool,
+
+    /// Open the run's index.html in the default browser when the run finishes. Does nothing
+    /// but warn when there's no browser to open, e.g. on a headless server.
+    #[arg(long)]
+    open_summary: bool,
+
+    /// Only print the progress counter, warnings, errors and the final summary, not per-URL
+    /// details.
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print more detail: `-v` adds Lighthouse commands and output, `-vv` everything.
+    /// `RUST_LOG` takes precedence when set.
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Read URLs from a sitemap.xml (local path or http(s) URL) instead of --file. Sitemap
+    /// index files are followed into their sub-sitemaps.
+    #[arg(long, conflicts_with_all = ["file", "stdin"])]
+    sitemap: Option<String>,
+
+    /// Read URLs from a JSON file instead of --file. Each entry has a `url` and may override
+    /// `form_factor`, `categories` and `extra_headers` for that URL.
+    #[arg(long, conflicts_with_all = ["file", "stdin", "sitemap"])]
+    config: Option<String>,
+
+    /// Discover URLs by crawling same-origin `<a href>` links from this seed page instead of
+    /// reading --file. Requires the `http` feature.
+    #[arg(long, conflicts_with_all = ["file", "stdin", "sitemap", "config"])]
+    crawl: Option<String>,
+
+    /// Re-audit the URLs in the `failures.txt` of an earlier run's output directory instead
+    /// of reading --file. A `--flat` run's `<prefix>_failures.txt` can be given directly.
+    #[arg(long, value_name = "RUN_DIR", conflicts_with_all = ["file", "stdin", "sitemap", "config", "crawl"])]
+    retry_failures: Option<String>,
+
+    /// How many links away from the seed page --crawl follows.
+    #[arg(long, default_value_t = 2)]
+    max_depth: usize,
+
+    /// Stop --crawl once this many pages have been found.
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u64).range(1..))]
+    max_pages: u64,
+
+    /// Only audit URLs containing this text (or matching this regex with --regex). Repeat for
+    /// more; a URL matching any of them is kept.
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// Skip URLs containing this text (or matching this regex with --regex). Repeat for more.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Treat --include and --exclude patterns as regular expressions instead of substrings.
+    #[arg(long)]
+    regex: bool,
+
+    /// Only audit this job's share of the URLs when splitting a batch across parallel jobs,
+    /// as `<index>/<total>` counting from 1, e.g. `--shard 2/4`. URLs are assigned by a hash,
+    /// so reruns of a shard cover the same URLs.
+    #[arg(long, value_name = "INDEX/TOTAL")]
+    shard: Option<Shard>,
+
+    /// Skip URLs disallowed by their site's robots.txt. Always on for --crawl.
+    #[arg(long, conflicts_with = "ignore_robots")]
+    respect_robots: bool,
+
+    /// Don't check robots.txt, even when crawling. For internal or staging sites you own.
+    #[arg(long)]
+    ignore_robots: bool,
+
+    /// Print the report index, with run details and every URL's scores, to `summary.pdf` using
+    /// headless Chrome. Requires the `pdf` feature.
+    #[arg(long)]
+    pdf: bool,
+
+    /// SQLite database to add every URL's scores to after the run, created if needed, for
+    /// tracking scores over time. Requires the `db` feature.
+    #[arg(long)]
+    db: Option<String>,
+
+    /// Write the run's metrics to this file in Prometheus text format, for the node exporter's
+    /// textfile collector.
+    #[arg(long)]
+    metrics_file: Option<String>,
+
+    /// Write a JUnit XML report to this file, with one test case per URL that fails when the
+    /// audit failed or a score fell below the thresholds.
+    #[arg(long, value_name = "PATH")]
+    junit: Option<String>,
+
+    /// Write a GitHub-flavored markdown summary to this file, for posting as a PR comment:
+    /// the average performance score, the lowest-scoring URLs and a collapsed list of all.
+    #[arg(long, value_name = "PATH")]
+    markdown: Option<String>,
+
+    /// Delete all but the KEEP most recent run folders of --name in --reports-dir and exit
+    /// without running a batch. Asks before deleting unless --yes; --dry-run only lists them.
+    #[arg(long, value_name = "KEEP", value_parser = clap::value_parser!(u32).range(1..))]
+    clean: Option<u32>,
+
+    /// Delete with --clean without asking for confirmation.
+    #[arg(long, requires = "clean")]
+    yes: bool,
+
+    /// Print the score history of this URL from --db and exit without running a batch.
+    #[arg(long, requires = "db")]
+    query_trend: Option<String>,
+
+    /// Rebuild the summaries of an earlier run (summary.csv, index.html, manifest.json and
+    /// failures.txt) from the JSON reports in RUN_DIR and exit without running Lighthouse.
+    /// The current thresholds decide the exit status. For a `--flat` run, pass its
+    /// `<prefix>_manifest.json`, or the reports folder when it holds only one run.
+    #[arg(long, value_name = "RUN_DIR", conflicts_with_all = ["file", "stdin", "sitemap", "config", "crawl", "retry_failures", "resume", "dry_run"])]
+    summary_only: Option<String>,
+
+    /// Upload the output directory to this S3 bucket after the batch, using credentials from
+    /// the standard AWS environment variables. Requires the `s3` feature.
+    #[arg(long)]
+    s3_bucket: Option<String>,
+
+    /// Key prefix for uploaded reports; each run is stored under `<prefix>/<output dir name>/`.
+    #[arg(long, requires = "s3_bucket", default_value = "")]
+    s3_prefix: String,
+
+    /// Slack incoming-webhook URL to post a summary to when the batch finishes (or set
+    /// `BATCH_ANALYZER_SLACK_WEBHOOK`).
+    #[arg(long)]
+    slack_webhook: Option<String>,
+
+    /// Output directory of an earlier run to compare scores against, or its manifest.json
+    /// (needed for a `--flat` run when the reports folder holds several).
+    #[arg(long)]
+    baseline: Option<String>,
+
+    /// Score drop (in points) beyond which a category counts as a regression with --baseline.
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(0..=100))]
+    regression_threshold: u32,
+
+    /// Settings file to read instead of `.batch-analyzer.toml` in the current directory.
+    #[arg(long)]
+    config_file: Option<String>,
+
+    /// Run a batch (the default) or one of these commands instead.
+    #[command(subcommand)]
+    command: Option<Commands>,
 }
 
-fn main() {
-    // Load environment variables from .env file if it exists
-    dotenv().ok();
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Check that Lighthouse, Node.js and Chrome are installed, the reports directory is
+    /// writable and the network is reachable, printing a checklist. Uses the same
+    /// --lighthouse-bin, --chrome-path and --reports-dir as a batch.
+    Doctor {
+        /// URL to check network access with.
+        #[arg(long, default_value = "https://example.com")]
+        url: String,
+    },
+}
 
-    let args = Args::parse();
-
-    // Get the name from environment variable first, then command line argument
-    let name = match env::var("BATCH_ANALYZER_NAME") {
-        Ok(env_name) => {
-            // If environment variable is set, use it and skip the name flag
-            println!("Using name from environment variable: {}", env_name);
-            env_name
-        }
-        Err(_) => {
-            // If no environment variable, check command line argument
-            match args.name {
-                Some(name) => name,
-                None => {
-                    eprintln!("Error: Name is required. Provide it via --name flag or set BATCH_ANALYZER_NAME environment variable in a .env file.");
-                    std::process::exit(1);
-                }
+/// Timestamp format of run folder names unless `--timestamp-format` says otherwise.
+const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y%m%d_%H%M%S";
+
+/// Settings file looked up in the current directory when `--config-file` isn't given.
+const DEFAULT_CONFIG_FILE: &str = ".batch-analyzer.toml";
+
+/// Team-wide defaults from a `.batch-analyzer.toml` file. Every key is optional, and flags
+/// given on the command line win over the file.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    name: Option<String>,
+    reports_dir: Option<String>,
+    format: Option<OutputFormat>,
+    concurrency: Option<Concurrency>,
+    throttling: Option<Throttling>,
+    #[serde(default)]
+    thresholds: Thresholds,
+}
+
+impl FileConfig {
+    /// Reads `--config-file`, or `.batch-analyzer.toml` if present. Returns the path that was
+    /// read alongside the settings, or `None` when there is no file to read.
+    fn load(explicit: Option<&str>) -> Result<Option<(String, FileConfig)>, String> {
+        let path = match explicit {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => DEFAULT_CONFIG_FILE,
+            None => return Ok(None),
+        };
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let config = FileConfig::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Some((path.to_string(), config)))
+    }
+
+    fn parse(text: &str) -> Result<FileConfig, String> {
+        let config: FileConfig = toml::from_str(text).map_err(|e| e.to_string())?;
+        let thresholds = &config.thresholds;
+        for score in [
+            thresholds.performance,
+            thresholds.accessibility,
+            thresholds.best_practices,
+            thresholds.seo,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if score > 100 {
+                return Err(format!("threshold {} is not in 0..=100", score));
             }
         }
-    };
-
-    // Get the report prefix from environment variable, default to "report" if not set
-    let report_prefix =
-        env::var("BATCH_ANALYZER_REPORT_PREFIX").unwrap_or_else(|_| "report".to_string());
-    println!("Using report prefix: {}", report_prefix);
+        Ok(config)
+    }
 
-    // --- 1. Create the reports directory and timestamped output directory ---
-    let reports_dir = Path::new(&args.reports_dir);
+    /// Fills in every setting that wasn't given on the command line.
+    fn apply(self, args: &mut Args, matches: &ArgMatches) {
+        let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+        if unset("name") {
+            args.name = self.name.or(args.name.take());
+        }
+        if let Some(reports_dir) = self.reports_dir.filter(|_| unset("reports_dir")) {
+            args.reports_dir = reports_dir;
+        }
+        if let Some(format) = self.format.filter(|_| unset("format")) {
+            args.format = format;
+        }
+        if let Some(concurrency) = self.concurrency.filter(|_| unset("concurrency")) {
+            args.concurrency = concurrency;
+        }
+        if unset("throttling") {
+            args.throttling = self.throttling.or(args.throttling);
+        }
+        if unset("min_performance") {
+            args.min_performance = self.thresholds.performance;
+        }
+        if unset("min_accessibility") {
+            args.min_accessibility = self.thresholds.accessibility;
+        }
+        if unset("min_best_practices") {
+            args.min_best_practices = self.thresholds.best_practices;
+        }
+        if unset("min_seo") {
+            args.min_seo = self.thresholds.seo;
+        }
+    }
+}
 
-    // Create the reports directory if it doesn't exist
-    if !reports_dir.exists() {
-        fs::create_dir_all(reports_dir).expect("Failed to create reports directory");
-        println!("Created reports directory: {}", args.reports_dir);
+/// Formats `time` with a `--timestamp-format`, rejecting formats chrono can't render and
+/// results that can't be part of a file name.
+fn format_timestamp<Tz: TimeZone>(time: &DateTime<Tz>, format: &str) -> Result<String, String>
+where
+    Tz::Offset: std::fmt::Display,
+{
+    // chrono keeps yielding `Item::Error` after an invalid specifier, so stop at the first
+    let mut items = Vec::new();
+    for item in StrftimeItems::new(format) {
+        if item == Item::Error {
+            return Err("not a valid strftime format".to_string());
+        }
+        items.push(item);
+    }
+    let timestamp = time.format_with_items(items.into_iter()).to_string();
+    if let Some(c) = timestamp.chars().find(|c| {
+        matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control()
+    }) {
+        return Err(format!(
+            "'{}' contains '{}', which can't be used in a file name",
+            timestamp,
+            c.escape_default()
+        ));
+    }
+    if timestamp.trim_matches('.').is_empty() {
+        return Err(format!("'{}' can't be used as a file name", timestamp));
     }
+    Ok(timestamp)
+}
 
-    let timestamp = Local::now().format("%Y%m%d_%H%M%S");
-    let dir_name = format!("{}_{}", name, timestamp);
-    let output_dir = reports_dir.join(&dir_name);
+/// How many `_1`, `_2`, ... suffixes to try when the run folder's name is already taken.
+const MAX_RUN_DIR_SUFFIX: u32 = 1000;
 
-    if !output_dir.exists() {
-        fs::create_dir(&output_dir).expect("Failed to create output directory");
-        println!("Created output directory: {}", output_dir.display());
+/// Creates a new folder at `base`, or at `base_1`, `base_2`, ... when that name is already
+/// taken, e.g. by another run started in the same second. Creating the folder is the check, so
+/// two runs racing for a name can't both get it.
+fn create_unique_dir(base: &Path) -> io::Result<PathBuf> {
+    let mut candidate = base.to_path_buf();
+    for suffix in 1..=MAX_RUN_DIR_SUFFIX {
+        match fs::create_dir(&candidate) {
+            Ok(()) => return Ok(candidate),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let mut name = base.file_name().unwrap_or_default().to_os_string();
+                name.push(format!("_{}", suffix));
+                candidate = base.with_file_name(name);
+            }
+            Err(e) => return Err(e),
+        }
     }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!(
+            "{} more folders with this name already exist",
+            MAX_RUN_DIR_SUFFIX
+        ),
+    ))
+}
 
-    // --- 2. Read URLs from the specified file ---
-    let urls_file = &args.file;
-    if let Ok(lines) = read_lines(urls_file) {
-        println!("Reading URLs from {}", urls_file);
-        for (index, line) in lines.enumerate() {
-            if let Ok(url) = line {
-                let url = url.trim();
-                if url.is_empty() {
-                    continue;
-                }
-                println!("\nAnalyzing URL ({}): {}", index + 1, url);
-
-                // --- 3. Run Lighthouse for each URL ---
-                let report_file_name = url_to_filename(url, &report_prefix);
-                let report_path = output_dir.join(&report_file_name);
-
-                let mut lighthouse_command = Command::new("lighthouse");
-                lighthouse_command
-                    .arg(url)
-                    .arg("--output=html")
-                    .arg(format!("--output-path={}", report_path.to_str().unwrap()))
-                    .arg("--view");
-
-                // Add chrome flags to run in a headless environment and disable cache
-                lighthouse_command.arg("--chrome-flags=--headless --no-sandbox --disable-cache");
-
-                let output = lighthouse_command
-                    .output()
-                    .expect("Failed to execute Lighthouse command. Is it installed globally?");
-
-                if output.status.success() {
-                    println!(
-                        "Successfully generated report: {}",
-                        report_path.to_str().unwrap()
-                    );
-                } else {
-                    eprintln!("Lighthouse failed for URL: {}", url);
-                    eprintln!("Stderr: {}", String::from_utf8_lossy(&output.stderr));
-                }
+/// Creates the run's own folder under the reports directory, noting in `created` that it
+/// is new so an empty run can remove it again.
+fn create_run_dir(output_dir: &Path, created: &mut bool) -> PathBuf {
+    match create_unique_dir(output_dir) {
+        Ok(dir) => {
+            if dir != output_dir {
+                warn!(
+                    "Output directory '{}' already exists; using '{}' instead",
+                    output_dir.display(),
+                    dir.display()
+                );
             }
+            info!("Created output directory: {}", dir.display());
+            *created = true;
+            dir
         }
-        println!(
-            "\nAnalysis complete. Reports are saved in '{}'",
-            output_dir.display()
-        );
-    } else {
-        eprintln!(
-            "Error: Could not open or read '{}'. Please make sure the file exists.",
-            urls_file
+        Err(e) => {
+            error!(
+                "Failed to create output directory '{}': {}",
+                output_dir.display(),
+                e
+            );
+            std::process::exit(ExitCode::Usage.code());
+        }
+    }
+}
+
+/// Puts together the Chrome flags for the batch: the defaults or --chrome-flags, then
+/// --extra-chrome-flags, minus `--disable-cache` with --keep-cache, plus the `--proxy-server`
+/// and `--user-data-dir` flags for --proxy and --user-data-dir, which win over the same flags
+/// given any other way.
+fn chrome_flags(args: &Args) -> Result<Vec<String>, String> {
+    let mut flags = match &args.chrome_flags {
+        Some(flags) => split_flags(flags),
+        None => DEFAULT_CHROME_FLAGS.iter().map(|f| f.to_string()).collect(),
+    };
+    if let Some(extra) = &args.extra_chrome_flags {
+        flags.extend(split_flags(extra));
+    }
+    if args.keep_cache {
+        flags.retain(|f| f != "--disable-cache");
+    }
+    if flags.iter().any(|f| f.starts_with("--user-agent=")) {
+        warn!("Lighthouse's emulation overrides Chrome's --user-agent flag; use --user-agent to change the user agent");
+    }
+    let mut replace = |name: &str, prefix: &str, flag: String| {
+        if flags.iter().any(|f| f.starts_with(prefix)) {
+            warn!("{} replaces the {} given in the Chrome flags", name, prefix);
+            flags.retain(|f| !f.starts_with(prefix));
+        }
+        flags.push(flag);
+    };
+    if let Some(proxy) = &args.proxy {
+        let flag =
+            proxy_chrome_flag(proxy).map_err(|e| format!("Invalid --proxy '{}': {}", proxy, e))?;
+        replace("--proxy", "--proxy-server=", flag);
+    }
+    if let Some(dir) = &args.user_data_dir {
+        if args.concurrency.resolve() > 1 {
+            return Err(
+                "--user-data-dir needs --concurrency 1: Chrome can't run two instances on one profile."
+                    .to_string(),
+            );
+        }
+        replace(
+            "--user-data-dir",
+            "--user-data-dir=",
+            format!("--user-data-dir={}", dir),
         );
     }
+    Ok(flags)
 }
 
-/// Converts a URL into a safe filename with prefix and base16 hash of the URL.
-/// Example: "https://www.google.com/search?q=rust" -> "report_a1b2c3d4e5f6.html"
-fn url_to_filename(url: &str, prefix: &str) -> String {
-    // Generate SHA-256 hash of the URL
-    let mut hasher = Sha256::new();
-    hasher.update(url.as_bytes());
-    let hash_result = hasher.finalize();
+/// The Lighthouse executable: the flag wins over the environment variable, which wins over a
+/// global install.
+fn lighthouse_bin(args: &Args) -> String {
+    args.lighthouse_bin
+        .clone()
+        .or_else(|| env::var("BATCH_ANALYZER_LIGHTHOUSE_BIN").ok())
+        .unwrap_or_else(|| "lighthouse".to_string())
+}
 
-    // Convert to hexadecimal string and take first 12 characters for reasonable filename length
-    let hash_hex = format!("{:x}", hash_result);
-    let short_hash = &hash_hex[..12];
+/// The Chrome executable, if one was chosen: the flag wins over the environment variable, and
+/// without either Lighthouse searches for Chrome itself.
+fn chrome_path(args: &Args) -> Option<PathBuf> {
+    args.chrome_path
+        .clone()
+        .or_else(|| env::var("BATCH_ANALYZER_CHROME_PATH").ok())
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
 
-    format!("{}_{}.html", prefix, short_hash)
+/// Runs the `doctor` checks and prints the checklist, returning whether all of them passed.
+fn doctor(args: &Args, url: &str) -> bool {
+    let checks = [
+        check_lighthouse(&lighthouse_bin(args)),
+        check_node(),
+        check_chrome(chrome_path(args).as_deref(), args.chrome_channel),
+        check_writable(Path::new(&args.reports_dir)),
+        check_network(url),
+    ];
+    for line in format_checklist(&checks).lines() {
+        info!("{}", line);
+    }
+    let failed = checks.iter().filter(|check| !check.passed).count();
+    if failed == 0 {
+        info!("All checks passed.");
+    } else {
+        error!("{} of {} check(s) failed.", failed, checks.len());
+    }
+    failed == 0
 }
 
-/// Reads a file line by line and returns an iterator over the lines.
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+/// Deletes all but the `keep` newest run folders of `prefix` in the reports directory, after
+/// listing them and asking for confirmation. Only folders named like this tool's runs are
+/// considered, so nothing else in the directory is touched.
+fn clean(args: &Args, prefix: &str, keep: usize) -> Result<(), String> {
+    let reports_dir = Path::new(&args.reports_dir);
+    let runs = find_runs(reports_dir, prefix, &args.timestamp_format)
+        .map_err(|e| format!("Could not read {}: {}", reports_dir.display(), e))?;
+    if runs.len() <= keep {
+        info!(
+            "{} run folder(s) of '{}' in {}, nothing to delete.",
+            runs.len(),
+            prefix,
+            reports_dir.display()
+        );
+        return Ok(());
+    }
+    let (kept, old) = runs.split_at(keep);
+    info!(
+        "Keeping the {} newest run folder(s) of '{}', the oldest kept from {}.",
+        kept.len(),
+        prefix,
+        kept[kept.len() - 1].started
+    );
+    info!("{} older run folder(s):", old.len());
+    for run in old {
+        info!("  {}", run.path.display());
+    }
+    if args.dry_run {
+        info!("Dry run: nothing deleted.");
+        return Ok(());
+    }
+    if !args.yes {
+        if !io::stdin().is_terminal() {
+            return Err("Not deleting without confirmation; pass --yes to --clean when not running interactively.".to_string());
+        }
+        eprint!("Delete {} folder(s)? [y/N] ", old.len());
+        let mut answer = String::new();
+        io::stdin()
+            .read_line(&mut answer)
+            .map_err(|e| e.to_string())?;
+        if !matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes") {
+            info!("Nothing deleted.");
+            return Ok(());
+        }
+    }
+    for run in old {
+        fs::remove_dir_all(&run.path)
+            .map_err(|e| format!("Failed to delete {}: {}", run.path.display(), e))?;
+    }
+    info!("Deleted {} run folder(s).", old.len());
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::env;
-    use std::fs::{self, File};
-    use std::io::Write;
-    use std::path::PathBuf;
+fn main() {
+    // Load environment variables from .env file if it exists
+    dotenv().ok();
 
-    #[test]
-    fn test_url_to_filename_basic() {
-        let url = "https://www.google.com";
-        let result = url_to_filename(url, "test");
-        assert!(result.starts_with("test_"));
-        assert!(result.ends_with(".html"));
-        // Should be in format: test_XXXXXXXXXXXX.html (where X is 12-char hex hash)
-        assert_eq!(result.len(), "test_".len() + 12 + ".html".len());
+    // clap exits with 2 on bad arguments, which the exit code contract reserves for failed audits
+    let exit_on_clap_error = |e: clap::Error| -> ! {
+        e.print().ok();
+        std::process::exit(if e.use_stderr() {
+            ExitCode::Usage.code()
+        } else {
+            ExitCode::Success.code()
+        })
+    };
+    let matches = Args::command()
+        .try_get_matches()
+        .unwrap_or_else(|e| exit_on_clap_error(e));
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| exit_on_clap_error(e));
+    init_logging(args.verbose, args.quiet);
 
-        // Test deterministic behavior - same URL should produce same filename
-        let result2 = url_to_filename(url, "test");
-        assert_eq!(result, result2);
+    // Command line flags win over the settings file, which wins over environment variables
+    match FileConfig::load(args.config_file.as_deref()) {
+        Ok(Some((path, config))) => {
+            info!("Using settings from {}", path);
+            config.apply(&mut args, &matches);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Could not read settings file {}", e);
+            std::process::exit(ExitCode::Usage.code());
+        }
     }
 
-    #[test]
-    fn test_url_to_filename_with_path() {
-        let url = "https://www.example.com/path/to/page";
-        let result = url_to_filename(url, "report");
-        assert!(result.starts_with("report_"));
-        assert!(result.ends_with(".html"));
-        assert_eq!(result.len(), "report_".len() + 12 + ".html".len());
+    if let Some(Commands::Doctor { url }) = &args.command {
+        let code = if doctor(&args, url) {
+            ExitCode::Success
+        } else {
+            ExitCode::Usage
+        };
+        std::process::exit(code.code());
+    }
 
-        // Test deterministic behavior
-        let result2 = url_to_filename(url, "report");
-        assert_eq!(result, result2);
+    if args.db.is_some() && !cfg!(feature = "db") {
+        error!(
+            "--db needs batch_analyzer built with the `db` feature (cargo install --features db)."
+        );
+        std::process::exit(ExitCode::Usage.code());
     }
 
-    #[test]
-    fn test_url_to_filename_with_query_params() {
-        let url = "https://www.google.com/search?q=rust&hl=en";
-        let result = url_to_filename(url, "test");
-        assert!(result.starts_with("test_"));
-        assert!(result.ends_with(".html"));
-        assert_eq!(result.len(), "test_".len() + 12 + ".html".len());
+    #[cfg(feature = "db")]
+    if let (Some(url), Some(db)) = (&args.query_trend, &args.db) {
+        let url = normalize_url(url).unwrap_or_else(|_| url.clone());
+        match batch_analyzer::score_history(Path::new(db), &url) {
+            Ok(rows) if rows.is_empty() => warn!("No scores recorded for {} in {}", url, db),
+            Ok(rows) => {
+                info!("Score history of {}:", url);
+                for line in batch_analyzer::format_trend(&rows).lines() {
+                    info!("{}", line);
+                }
+            }
+            Err(e) => {
+                error!("Could not read {}: {}", db, e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        }
+        return;
     }
 
-    #[test]
-    fn test_url_to_filename_http_protocol() {
-        let url = "http://example.com/test";
-        let result = url_to_filename(url, "myprefix");
-        assert!(result.starts_with("myprefix_"));
-        assert!(result.ends_with(".html"));
-        assert_eq!(result.len(), "myprefix_".len() + 12 + ".html".len());
+    if let Some(run_dir) = &args.summary_only {
+        if args.pdf && !cfg!(feature = "pdf") {
+            error!("--pdf needs batch_analyzer built with the `pdf` feature (cargo install --features pdf).");
+            std::process::exit(ExitCode::Usage.code());
+        }
+        let (output_dir, file_prefix) = match locate_run(Path::new(run_dir)) {
+            Ok(run) => run,
+            Err(e) => {
+                error!("Cannot summarize the run: {}", e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        };
+        let config = BatchConfig {
+            // Only used when the run has no manifest to take its name from
+            name: args.name.clone().unwrap_or_else(|| {
+                output_dir
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            }),
+            output_dir,
+            options: AuditOptions {
+                budget_path: args.budget.clone(),
+                chrome_path: chrome_path(&args),
+                file_prefix,
+                ..Default::default()
+            },
+            fail_on_warnings: args.fail_on_warnings,
+            pdf: args.pdf,
+            db: args.db.as_ref().map(PathBuf::from),
+            metrics_file: args.metrics_file.as_ref().map(PathBuf::from),
+            junit: args.junit.as_ref().map(PathBuf::from),
+            markdown: args.markdown.as_ref().map(PathBuf::from),
+            open_summary: args.open_summary,
+            baseline: args.baseline.clone(),
+            regression_threshold: args.regression_threshold,
+            thresholds: Thresholds {
+                performance: args.min_performance,
+                accessibility: args.min_accessibility,
+                best_practices: args.min_best_practices,
+                seo: args.min_seo,
+            },
+            ..Default::default()
+        };
+        let report = match summarize_run(&config) {
+            Ok(report) => report,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        };
+        let exit_code = report.exit_code(args.fail_on_budget);
+        if exit_code != ExitCode::Success {
+            std::process::exit(exit_code.code());
+        }
+        return;
     }
 
-    #[test]
-    fn test_url_to_filename_special_characters() {
-        let url = "https://example.com/path/with-special@chars#fragment";
-        let result = url_to_filename(url, "test");
-        assert!(result.starts_with("test_"));
-        assert!(result.ends_with(".html"));
-        assert_eq!(result.len(), "test_".len() + 12 + ".html".len());
+    let name = match args.name.clone() {
+        Some(name) => name,
+        None => match env::var("BATCH_ANALYZER_NAME") {
+            Ok(env_name) => {
+                info!("Using name from environment variable: {}", env_name);
+                env_name
+            }
+            Err(_) => {
+                error!("Name is required. Provide it via --name, the settings file or the BATCH_ANALYZER_NAME environment variable.");
+                std::process::exit(ExitCode::Usage.code());
+            }
+        },
+    };
+    // The name becomes part of a directory name, wherever it came from
+    let dir_prefix = sanitize_name(&name);
+    if dir_prefix.is_empty() {
+        error!(
+            "Name '{}' has no characters usable in a directory name.",
+            name
+        );
+        std::process::exit(ExitCode::Usage.code());
     }
 
-    #[test]
-    fn test_url_to_filename_long_url_truncation() {
-        let long_path = "a".repeat(120);
-        let url = format!("https://example.com/{}", long_path);
-        let result = url_to_filename(&url, "test");
+    if let Some(keep) = args.clean {
+        if let Err(e) = clean(&args, &dir_prefix, keep as usize) {
+            error!("{}", e);
+            std::process::exit(ExitCode::Usage.code());
+        }
+        return;
+    }
 
-        // Should only contain prefix and hash, regardless of URL length
-        assert!(result.starts_with("test_"));
-        assert!(result.ends_with(".html"));
-        assert_eq!(result.len(), "test_".len() + 12 + ".html".len());
+    // Get the report prefix from environment variable, default to "report" if not set
+    let report_prefix =
+        env::var("BATCH_ANALYZER_REPORT_PREFIX").unwrap_or_else(|_| "report".to_string());
+    info!("Using report prefix: {}", report_prefix);
+    let mut form_factors: Vec<FormFactor> = Vec::new();
+    for form_factor in &args.form_factor {
+        if !form_factors.contains(form_factor) {
+            form_factors.push(*form_factor);
+        }
     }
+    let form_factor_names: Vec<&str> = form_factors.iter().map(|f| f.as_str()).collect();
+    info!("Using form factor: {}", form_factor_names.join(", "));
+    if let Some(throttling) = args.throttling {
+        info!("Using throttling: {}", throttling.as_str());
+    }
+    let mut cookies = args.cookies.clone();
+    if let Some(path) = &args.cookies_file {
+        match read_cookies_file(path) {
+            Ok(from_file) => cookies.extend(from_file),
+            Err(e) => {
+                error!("Could not read cookies file '{}': {}", path, e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        }
+    }
+    let url_filter = match UrlFilter::new(&args.include, &args.exclude, args.regex) {
+        Ok(filter) => filter,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(ExitCode::Usage.code());
+        }
+    };
 
-    #[test]
-    fn test_url_to_filename_preserves_allowed_chars() {
-        let url = "https://sub-domain.example-site.com/path-with-dashes";
-        let result = url_to_filename(url, "report");
-        assert!(result.starts_with("report_"));
-        assert!(result.ends_with(".html"));
-        assert_eq!(result.len(), "report_".len() + 12 + ".html".len());
+    if let Some(locale) = args.locale.as_deref().filter(|locale| !is_locale(locale)) {
+        error!(
+            "Invalid --locale '{}': expected a language tag such as `de` or `pt-BR`",
+            locale
+        );
+        std::process::exit(ExitCode::Usage.code());
+    }
+    if args
+        .user_agent
+        .as_deref()
+        .is_some_and(|ua| ua.trim().is_empty())
+    {
+        error!("--user-agent needs a user agent string");
+        std::process::exit(ExitCode::Usage.code());
+    }
+    let mut extra_headers = match parse_headers(&args.headers)
+        .and_then(|headers| add_cookie_header(headers, &cookies))
+    {
+        Ok(headers) => headers,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(ExitCode::Usage.code());
+        }
+    };
+    if args.plugins.iter().any(|plugin| plugin.trim().is_empty()) {
+        error!("--plugin needs a plugin name, e.g. --plugin lighthouse-plugin-field-performance");
+        std::process::exit(ExitCode::Usage.code());
     }
 
-    #[test]
-    fn test_read_lines_existing_file() {
-        // Create a temporary test file
-        let temp_dir = env::temp_dir();
-        let test_file = temp_dir.join("test_urls.txt");
+    if (args.gather_only || args.audit_only.is_some()) && (args.runs > 1 || args.warmup) {
+        error!("--gather-only and --audit-only run Lighthouse once per URL; they can't be combined with --runs or --warmup.");
+        std::process::exit(ExitCode::Usage.code());
+    }
+    if let Some(run_dir) = &args.audit_only {
+        if !Path::new(run_dir).join("artifacts").is_dir() {
+            error!(
+                "No artifacts in '{}': --audit-only needs the output directory of a --gather-only run.",
+                run_dir
+            );
+            std::process::exit(ExitCode::Usage.code());
+        }
+    }
 
-        {
-            let mut file = File::create(&test_file).expect("Failed to create test file");
-            writeln!(file, "https://www.example1.com").expect("Failed to write to test file");
-            writeln!(file, "https://www.example2.com").expect("Failed to write to test file");
-            writeln!(file, "").expect("Failed to write to test file"); // Empty line
-            writeln!(file, "https://www.example3.com").expect("Failed to write to test file");
+    if let (true, Some(locale)) = (args.accept_language, &args.locale) {
+        let given = extra_headers
+            .keys()
+            .any(|key| key.eq_ignore_ascii_case("accept-language"));
+        if !given {
+            extra_headers.insert("Accept-Language".to_string(), locale.clone());
         }
+    }
+    if !extra_headers.is_empty() {
+        info!("Using extra headers: {}", mask_headers(&extra_headers));
+    }
 
-        let lines_result = read_lines(&test_file);
-        assert!(lines_result.is_ok());
+    // The flag wins over the environment variable
+    let slack_webhook = args
+        .slack_webhook
+        .clone()
+        .or_else(|| env::var("BATCH_ANALYZER_SLACK_WEBHOOK").ok())
+        .filter(|url| !url.is_empty());
 
-        let lines: Vec<String> = lines_result.unwrap().map(|line| line.unwrap()).collect();
+    if args.check_redirects && !cfg!(feature = "http") {
+        error!("--check-redirects needs batch_analyzer built with the `http` feature (cargo install --features http).");
+        std::process::exit(ExitCode::Usage.code());
+    }
 
-        assert_eq!(lines.len(), 4);
-        assert_eq!(lines[0], "https://www.example1.com");
-        assert_eq!(lines[1], "https://www.example2.com");
-        assert_eq!(lines[2], ""); // Empty line
-        assert_eq!(lines[3], "https://www.example3.com");
+    if args.preflight && !cfg!(feature = "http") {
+        error!("--preflight needs batch_analyzer built with the `http` feature (cargo install --features http).");
+        std::process::exit(ExitCode::Usage.code());
+    }
 
-        // Clean up
-        fs::remove_file(&test_file).expect("Failed to remove test file");
+    if args.pdf && !cfg!(feature = "pdf") {
+        error!("--pdf needs batch_analyzer built with the `pdf` feature (cargo install --features pdf).");
+        std::process::exit(ExitCode::Usage.code());
     }
 
-    #[test]
-    fn test_read_lines_nonexistent_file() {
-        let nonexistent_file = "/path/that/does/not/exist.txt";
-        let result = read_lines(nonexistent_file);
-        assert!(result.is_err());
+    if args.max_memory_percent.is_some() && !cfg!(feature = "memory") {
+        error!("--max-memory-percent needs batch_analyzer built with the `memory` feature (cargo install --features memory).");
+        std::process::exit(ExitCode::Usage.code());
     }
 
-    #[test]
-    fn test_url_to_filename_edge_cases() {
-        // Test empty-ish URL after protocol removal
-        let url1 = "https://";
-        let result1 = url_to_filename(url1, "test");
-        assert!(result1.starts_with("test_"));
-        assert!(result1.ends_with(".html"));
-        assert_eq!(result1.len(), "test_".len() + 12 + ".html".len());
-
-        // Test URL with only domain
-        let url2 = "https://a.com";
-        let result2 = url_to_filename(url2, "test");
-        assert!(result2.starts_with("test_"));
-        assert!(result2.ends_with(".html"));
-        assert_eq!(result2.len(), "test_".len() + 12 + ".html".len());
-
-        // Test URL with numbers
-        let url3 = "https://example123.com/path456";
-        let result3 = url_to_filename(url3, "test");
-        assert!(result3.starts_with("test_"));
-        assert!(result3.ends_with(".html"));
-        assert_eq!(result3.len(), "test_".len() + 12 + ".html".len());
-
-        // Test that different URLs produce different hashes
-        assert_ne!(result1, result2);
-        assert_ne!(result2, result3);
-        assert_ne!(result1, result3);
+    if args.s3_bucket.is_some() && !cfg!(feature = "s3") {
+        error!("--s3-bucket needs batch_analyzer built with the `s3` feature (cargo install --features s3).");
+        std::process::exit(ExitCode::Usage.code());
     }
 
-    #[test]
-    fn test_url_to_filename_unicode_characters() {
-        let url = "https://example.com/café/naïve";
-        let result = url_to_filename(url, "test");
-        // URL content affects the hash
-        assert!(result.starts_with("test_"));
-        assert!(result.ends_with(".html"));
-        assert_eq!(result.len(), "test_".len() + 12 + ".html".len());
+    let lighthouse_bin = lighthouse_bin(&args);
+    let lighthouse_check = match find_executable(&lighthouse_bin) {
+        Some(path) => {
+            info!("Using Lighthouse binary: {}", path.display());
+            lighthouse_version(&lighthouse_bin)
+        }
+        None => Err("not found".to_string()),
+    };
+    let lighthouse_version = match lighthouse_check {
+        Ok(version) => {
+            info!("Using Lighthouse version: {}", version);
+            Some(version)
+        }
+        Err(e) if args.dry_run => {
+            warn!(
+                "Lighthouse binary '{}' is not usable ({}). Continuing because this is a dry run.",
+                lighthouse_bin, e
+            );
+            None
+        }
+        Err(e) => {
+            error!("Lighthouse binary '{}' is not usable ({}). Install it globally (npm install -g lighthouse) or point --lighthouse-bin / BATCH_ANALYZER_LIGHTHOUSE_BIN at it.", lighthouse_bin, e);
+            std::process::exit(ExitCode::Usage.code());
+        }
+    };
+
+    let mut chrome_version = None;
+    let chrome_path = match args.chrome_channel {
+        Some(channel) => match channel.find() {
+            Ok(path) => {
+                chrome_version = batch_analyzer::chrome_version(&path).ok();
+                info!(
+                    "Using Chrome {}: {} ({})",
+                    channel.as_str(),
+                    path.display(),
+                    chrome_version.as_deref().unwrap_or("unknown version")
+                );
+                Some(path)
+            }
+            Err(e) if args.dry_run => {
+                warn!("{}. Continuing because this is a dry run.", e);
+                None
+            }
+            Err(e) => {
+                error!("{}. Install it or pick another --chrome-channel.", e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        },
+        None => chrome_path(&args),
+    };
+    if let (None, Some(path)) = (args.chrome_channel, &chrome_path) {
+        if path.is_file() {
+            info!("Using Chrome: {}", path.display());
+        } else if args.dry_run {
+            warn!(
+                "Chrome executable '{}' does not exist. Continuing because this is a dry run.",
+                path.display()
+            );
+        } else {
+            error!(
+                "Chrome executable '{}' does not exist. Fix --chrome-path / BATCH_ANALYZER_CHROME_PATH.",
+                path.display()
+            );
+            std::process::exit(ExitCode::Usage.code());
+        }
     }
 
-    #[test]
-    fn test_url_to_filename_multiple_consecutive_special_chars() {
-        let url = "https://example.com/path///with&&multiple@@special##chars";
-        let result = url_to_filename(url, "test");
-        assert!(result.starts_with("test_"));
-        assert!(result.ends_with(".html"));
-        assert_eq!(result.len(), "test_".len() + 12 + ".html".len());
+    if let Some(budget) = &args.budget {
+        // Catch a broken budget file now rather than in every Lighthouse run
+        let parsed = fs::read_to_string(budget)
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                serde_json::from_str::<Vec<serde_json::Value>>(&json).map_err(|e| e.to_string())
+            });
+        if let Err(e) = parsed {
+            error!("Invalid budget file '{}': {}", budget, e);
+            std::process::exit(ExitCode::Usage.code());
+        }
+        info!("Using budget: {}", budget);
     }
 
-    #[test]
-    fn test_url_to_filename_hash_uniqueness() {
-        let url1 = "https://example.com/page1";
-        let url2 = "https://example.com/page2";
-        let result1 = url_to_filename(url1, "test");
-        let result2 = url_to_filename(url2, "test");
+    let lighthouse_config = args.lh_config.as_ref().map(|path| {
+        // Catch a missing or broken config now rather than in every Lighthouse run
+        match LighthouseConfig::read(path) {
+            Ok(config) => {
+                info!(
+                    "Using Lighthouse config: {} (sha256 {})",
+                    path,
+                    &config.sha256[..12]
+                );
+                config
+            }
+            Err(e) => {
+                error!("Invalid Lighthouse config '{}': {}", path, e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        }
+    });
+    if lighthouse_config.is_some() && form_factors.contains(&FormFactor::Desktop) {
+        warn!("Lighthouse ignores its desktop preset when given a config; desktop runs use the --lh-config settings as they are.");
+    }
+
+    // --- 1. Create the reports directory and timestamped output directory ---
+    let formatted = if args.utc {
+        format_timestamp(&Utc::now(), &args.timestamp_format)
+    } else {
+        format_timestamp(&Local::now(), &args.timestamp_format)
+    };
+    let timestamp = match formatted {
+        Ok(timestamp) => timestamp,
+        Err(e) => {
+            error!(
+                "Invalid --timestamp-format '{}': {}",
+                args.timestamp_format, e
+            );
+            std::process::exit(ExitCode::Usage.code());
+        }
+    };
+    let mut created_output_dir = false;
+    let fixed_output_dir = args.output_dir.clone().or_else(|| {
+        env::var("BATCH_ANALYZER_OUTPUT_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty() && !args.flat)
+    });
+    // Desktop runs are tagged so they never collide with a mobile run of the same name
+    let dir_name = match form_factors.as_slice() {
+        [FormFactor::Mobile] => format!("{}_{}", dir_prefix, timestamp),
+        _ => format!(
+            "{}_{}_{}",
+            dir_prefix,
+            form_factor_names.join("_"),
+            timestamp
+        ),
+    };
+    let output_dir = match &fixed_output_dir {
+        Some(dir) => {
+            let output_dir = PathBuf::from(dir);
+            if !output_dir.exists() {
+                if let Err(e) = fs::create_dir_all(&output_dir) {
+                    error!("Failed to create output directory '{}': {}", dir, e);
+                    std::process::exit(ExitCode::Usage.code());
+                }
+                info!("Created output directory: {}", output_dir.display());
+                created_output_dir = true;
+            } else if !output_dir.is_dir() {
+                error!("Output directory '{}' exists but is not a directory", dir);
+                std::process::exit(ExitCode::Usage.code());
+            }
+            output_dir
+        }
+        None => {
+            let reports_dir = Path::new(&args.reports_dir);
 
-        // Different URLs should produce different hashes
-        assert_ne!(result1, result2);
+            // Create the reports directory if it doesn't exist
+            if !reports_dir.exists() {
+                if let Err(e) = fs::create_dir_all(reports_dir) {
+                    error!(
+                        "Failed to create reports directory '{}': {}",
+                        args.reports_dir, e
+                    );
+                    std::process::exit(ExitCode::Usage.code());
+                }
+                info!("Created reports directory: {}", args.reports_dir);
+            }
 
-        // Same URL should always produce same hash
-        let result1_again = url_to_filename(url1, "test");
-        assert_eq!(result1, result1_again);
+            if args.flat {
+                reports_dir.to_path_buf()
+            } else {
+                create_run_dir(&reports_dir.join(&dir_name), &mut created_output_dir)
+            }
+        }
+    };
+
+    if args.resume && fixed_output_dir.is_none() {
+        warn!("--resume has no effect on a new timestamped folder; use --output-dir to point at an existing run.");
     }
 
-    // Integration test helper for creating temporary URLs file
-    fn create_temp_urls_file(urls: &[&str]) -> PathBuf {
-        let temp_dir = env::temp_dir();
-        let test_file = temp_dir.join(format!("test_urls_{}.txt", std::process::id()));
+    let requested = args.concurrency.resolve();
+    let concurrency = requested.clamp(1, MAX_CONCURRENCY);
+    if args.concurrency == Concurrency::Auto {
+        info!("Using --concurrency {} (auto).", concurrency);
+    } else if concurrency != requested {
+        warn!(
+            "--concurrency {} is out of range, using {} instead.",
+            requested, concurrency
+        );
+    }
 
-        {
-            let mut file = File::create(&test_file).expect("Failed to create test file");
-            for url in urls {
-                writeln!(file, "{}", url).expect("Failed to write to test file");
+    // --- 2. Read URLs from the sitemap, config or the specified file ---
+    let server = args.serve_dir.as_ref().map(|dir| {
+        match StaticServer::start(Path::new(dir), args.serve_port) {
+            Ok(server) => {
+                info!("Serving {} at http://127.0.0.1:{}/", dir, server.port());
+                server
+            }
+            Err(e) => {
+                error!("Cannot serve --serve-dir '{}': {}", dir, e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        }
+    });
+    // Local files must exist by now, so a typo is caught before any audit runs
+    let normalize_entry = |raw: &str| {
+        if is_local_path(raw) {
+            local_url(raw, server.as_ref())
+        } else {
+            normalize_url(raw)
+        }
+    };
+    let mut overrides: HashMap<usize, UrlOverrides> = HashMap::new();
+    let mut labels: HashMap<usize, String> = HashMap::new();
+    let mut groups: HashMap<usize, String> = HashMap::new();
+    let remote_input = !args.stdin && is_http_url(&args.file);
+    if remote_input && args.url_column.is_some() {
+        error!("--url-column can't be used with a URL list downloaded over http(s).");
+        std::process::exit(ExitCode::Usage.code());
+    }
+    let yaml_input = !args.stdin && !remote_input && {
+        let file = args.file.to_lowercase();
+        file.ends_with(".yaml") || file.ends_with(".yml")
+    };
+    let other_source = args.retry_failures.is_some()
+        || args.sitemap.is_some()
+        || args.crawl.is_some()
+        || args.config.is_some()
+        || args.url_column.is_some();
+    if !args.group.is_empty() && (!yaml_input || other_source) {
+        error!("--group only applies to a YAML --file (.yaml or .yml) of URL groups.");
+        std::process::exit(ExitCode::Usage.code());
+    }
+    let mut funnel = UrlFunnel::default();
+    let urls: Vec<(usize, String)> = if let Some(run_dir) = &args.retry_failures {
+        info!("Retrying the failed URLs of {}", run_dir);
+        match read_failures(Path::new(run_dir)) {
+            Ok(urls) => {
+                funnel.read = urls.len();
+                urls.into_iter().enumerate().collect()
+            }
+            Err(e) => {
+                error!("Cannot retry failures: {}", e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        }
+    } else if let Some(sitemap) = &args.sitemap {
+        info!("Reading URLs from sitemap {}", sitemap);
+        match sitemap_urls(sitemap) {
+            Ok(urls) => {
+                funnel.read = urls.len();
+                urls.into_iter().enumerate().collect()
+            }
+            Err(e) => {
+                error!("Could not read sitemap '{}': {}", sitemap, e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        }
+    } else if let Some(seed) = &args.crawl {
+        let seed = match normalize_url(seed) {
+            Ok(seed) => seed,
+            Err(e) => {
+                error!("Invalid --crawl URL '{}': {}", seed, e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        };
+        info!(
+            "Crawling {} (depth {}, up to {} pages)",
+            seed, args.max_depth, args.max_pages
+        );
+        let mut robots = (!args.ignore_robots).then(RobotsCache::default);
+        match crawl(
+            &seed,
+            args.max_depth,
+            args.max_pages as usize,
+            robots.as_mut(),
+            fetch_text,
+        ) {
+            Ok(urls) => {
+                info!("Crawl found {} page(s)", urls.len());
+                funnel.read = urls.len();
+                urls.into_iter().enumerate().collect()
+            }
+            Err(e) => {
+                error!("Could not crawl '{}': {}", seed, e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        }
+    } else if let Some(config) = &args.config {
+        info!("Reading URLs from config {}", config);
+        let entries = match read_url_config(config) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Could not read config '{}': {}", config, e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        };
+        funnel.read = entries.len();
+        let mut urls: Vec<(usize, String)> = Vec::new();
+        for (index, entry) in entries.into_iter().enumerate() {
+            match normalize_entry(entry.url.trim()) {
+                Ok(url) => {
+                    urls.push((index, url));
+                    overrides.insert(index, entry.overrides);
+                }
+                Err(e) => {
+                    warn!(
+                        "Entry {}: skipping invalid URL '{}': {}",
+                        index + 1,
+                        mask_credentials(&entry.url),
+                        e
+                    );
+                    funnel.invalid += 1;
+                }
+            }
+        }
+        urls
+    } else if yaml_input && args.url_column.is_none() {
+        info!("Reading URL groups from {}", args.file);
+        let url_groups = match read_url_groups(&args.file) {
+            Ok(url_groups) => url_groups,
+            Err(e) => {
+                error!("Could not read YAML '{}': {}", args.file, e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        };
+        let unknown: Vec<&str> = args
+            .group
+            .iter()
+            .filter(|name| !url_groups.iter().any(|group| &group.name == *name))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            let known: Vec<&str> = url_groups.iter().map(|group| group.name.as_str()).collect();
+            error!(
+                "Unknown --group {} (groups in '{}': {})",
+                unknown.join(", "),
+                args.file,
+                known.join(", ")
+            );
+            std::process::exit(ExitCode::Usage.code());
+        }
+        let mut urls: Vec<(usize, String)> = Vec::new();
+        let mut index = 0;
+        for group in url_groups {
+            let selected = args.group.is_empty() || args.group.contains(&group.name);
+            for raw in &group.urls {
+                index += 1;
+                if !selected {
+                    funnel.unselected += 1;
+                    continue;
+                }
+                match normalize_entry(raw.trim()) {
+                    Ok(url) => {
+                        urls.push((index - 1, url));
+                        groups.insert(index - 1, group.name.clone());
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Group '{}': skipping invalid URL '{}': {}",
+                            group.name,
+                            mask_credentials(raw),
+                            e
+                        );
+                        funnel.invalid += 1;
+                    }
+                }
+            }
+        }
+        funnel.read = index;
+        if !args.group.is_empty() {
+            info!(
+                "Selected group(s) {}: {} URL(s)",
+                args.group.join(", "),
+                urls.len()
+            );
+        }
+        urls
+    } else if !remote_input
+        && (args.url_column.is_some() || args.file.to_lowercase().ends_with(".csv"))
+    {
+        let urls_file = if args.stdin { "-" } else { args.file.as_str() };
+        let column = args.url_column.as_deref().unwrap_or("url");
+        info!("Reading URLs from column '{}' of {}", column, urls_file);
+        let rows = match read_csv_urls(urls_file, column) {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("Could not read CSV '{}': {}", urls_file, e);
+                std::process::exit(ExitCode::Usage.code());
+            }
+        };
+        funnel.read = rows.len();
+        let mut urls: Vec<(usize, String)> = Vec::new();
+        for row in rows {
+            match normalize_entry(&row.url) {
+                Ok(url) => {
+                    urls.push((row.index, url));
+                    if let Some(label) = row.label {
+                        labels.insert(row.index, label);
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Line {}: skipping invalid URL '{}': {}",
+                        row.index + 1,
+                        mask_credentials(&row.url),
+                        e
+                    );
+                    funnel.invalid += 1;
+                }
             }
         }
+        urls
+    } else {
+        let urls_file = if args.stdin { "-" } else { args.file.as_str() };
+        let mut lines = if remote_input {
+            info!("Downloading the URL list from {}", urls_file);
+            match read_remote_lines(urls_file) {
+                Ok(lines) => lines,
+                Err(e) => {
+                    error!(
+                        "Could not download the URL list from '{}': {}",
+                        urls_file, e
+                    );
+                    if created_output_dir {
+                        fs::remove_dir(&output_dir).ok();
+                    }
+                    std::process::exit(ExitCode::Usage.code());
+                }
+            }
+        } else {
+            let Ok(lines) = read_lines(urls_file) else {
+                error!(
+                    "Could not open or read '{}'. Please make sure the file exists.",
+                    urls_file
+                );
+                if created_output_dir {
+                    fs::remove_dir(&output_dir).ok();
+                }
+                std::process::exit(ExitCode::Usage.code());
+            };
+            lines
+        };
+        if urls_file == "-" {
+            info!("Reading URLs from standard input");
+        } else {
+            info!("Reading URLs from {}", urls_file);
+        }
+        let mut urls: Vec<(usize, String)> = Vec::new();
+        for (index, line) in lines.by_ref().enumerate() {
+            funnel.read += 1;
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    error!(
+                        "Line {}: could not read '{}': {}; ignoring the rest of the file",
+                        index + 1,
+                        urls_file,
+                        e
+                    );
+                    funnel.invalid += 1;
+                    break;
+                }
+            };
+            let raw = strip_comment(&line);
+            if raw.is_empty() {
+                funnel.comments += 1;
+                continue;
+            }
+            let count = if args.expand_templates {
+                template_url_count(raw)
+            } else {
+                1
+            };
+            if count > MAX_TEMPLATE_URLS as u64 {
+                warn!(
+                    "Line {}: skipping template '{}', which expands to {} URLs (at most {} allowed)",
+                    index + 1,
+                    raw,
+                    count,
+                    MAX_TEMPLATE_URLS
+                );
+                funnel.invalid += 1;
+                continue;
+            }
+            let expanded = if args.expand_templates {
+                expand_template(raw)
+            } else {
+                vec![raw.to_string()]
+            };
+            funnel.expanded += expanded.len().saturating_sub(1);
+            for raw in &expanded {
+                match normalize_entry(raw) {
+                    Ok(url) => urls.push((index, url)),
+                    Err(e) => {
+                        warn!(
+                            "Line {}: skipping invalid URL '{}': {}",
+                            index + 1,
+                            mask_credentials(raw),
+                            e
+                        );
+                        funnel.invalid += 1;
+                    }
+                }
+            }
+        }
+        if !lines.lossy_lines.is_empty() {
+            let numbers: Vec<String> = lines.lossy_lines.iter().map(|n| n.to_string()).collect();
+            warn!(
+                "{} line(s) in '{}' are not valid UTF-8 (line {}); undecodable bytes were replaced with \u{FFFD}",
+                numbers.len(),
+                urls_file,
+                numbers.join(", ")
+            );
+        }
+        urls
+    };
 
-        test_file
+    // Credentials in a URL are sent as an Authorization header instead, so the URL is audited,
+    // named and logged without them. A header given for the URL in --config wins.
+    let mut with_credentials = 0;
+    let urls: Vec<(usize, String)> = urls
+        .into_iter()
+        .map(|(index, url)| match take_credentials(&url) {
+            Some((url, authorization)) => {
+                with_credentials += 1;
+                let headers = &mut overrides.entry(index).or_default().extra_headers;
+                if !headers
+                    .keys()
+                    .any(|key| key.eq_ignore_ascii_case("authorization"))
+                {
+                    headers.insert("Authorization".to_string(), authorization);
+                }
+                (index, url)
+            }
+            None => (index, url),
+        })
+        .collect();
+    if with_credentials > 0 {
+        info!(
+            "Sending the credentials of {} URL(s) as a basic auth Authorization header",
+            with_credentials
+        );
     }
 
-    #[test]
-    fn test_integration_multiple_urls() {
-        let test_urls = vec![
-            "https://www.google.com",
-            "https://github.com/rust-lang/rust",
-            "http://example.com/test?param=value",
-        ];
-
-        let temp_file = create_temp_urls_file(&test_urls);
-
-        // Test that we can read all URLs
-        let lines_result = read_lines(&temp_file);
-        assert!(lines_result.is_ok());
-
-        let urls: Vec<String> = lines_result
-            .unwrap()
-            .map(|line| line.unwrap().trim().to_string())
-            .filter(|line| !line.is_empty())
+    if funnel.invalid > 0 && args.strict {
+        error!(
+            "{} invalid URL(s) in the input. Aborting because --strict is set.",
+            funnel.invalid
+        );
+        std::process::exit(ExitCode::Usage.code());
+    }
+
+    let urls = if args.allow_duplicates {
+        urls
+    } else {
+        let (unique, removed) = dedup_urls(urls);
+        funnel.duplicates = removed;
+        if removed > 0 {
+            info!("Removed {} duplicate URL(s)", removed);
+        }
+        unique
+    };
+
+    let urls = if url_filter.is_empty() {
+        urls
+    } else {
+        let total = urls.len();
+        let kept: Vec<(usize, String)> = urls
+            .into_iter()
+            .filter(|(_, url)| url_filter.matches(url))
             .collect();
+        info!(
+            "URL filters matched {} of {} URL(s), {} filtered out",
+            kept.len(),
+            total,
+            total - kept.len()
+        );
+        funnel.excluded = total - kept.len();
+        kept
+    };
 
-        assert_eq!(urls.len(), 3);
+    let urls = match args.shard {
+        Some(shard) => {
+            let total = urls.len();
+            let owned: Vec<(usize, String)> = urls
+                .into_iter()
+                .filter(|(_, url)| shard.owns(url))
+                .collect();
+            info!(
+                "Shard {}/{} owns {} of {} URL(s)",
+                shard.index,
+                shard.total,
+                owned.len(),
+                total
+            );
+            funnel.other_shards = total - owned.len();
+            owned
+        }
+        None => urls,
+    };
 
-        // Test filename generation for each URL
-        let filenames: Vec<String> = urls
-            .iter()
-            .map(|url| url_to_filename(url, "test"))
+    let urls = if args.respect_robots && !args.ignore_robots {
+        let mut robots = RobotsCache::default();
+        let total = urls.len();
+        let allowed: Vec<(usize, String)> = urls
+            .into_iter()
+            .filter(|(_, url)| Url::parse(url).map_or(true, |url| robots.allows(&url, fetch_text)))
             .collect();
+        funnel.disallowed = total - allowed.len();
+        allowed
+    } else {
+        urls
+    };
+    funnel.remaining = urls.len();
+
+    if urls.is_empty() {
+        // Don't leave an empty folder behind for a run that never started
+        if created_output_dir {
+            fs::remove_dir(&output_dir).ok();
+        }
+        if args.continue_on_empty_file {
+            warn!(
+                "No URLs left to analyze ({}); nothing to do.",
+                funnel.summary()
+            );
+            return;
+        }
+        // With few URLs a shard can come up empty, which isn't an error in the input
+        if let Some(shard) = args.shard {
+            warn!(
+                "Shard {}/{} owns none of the URLs ({}); nothing to analyze.",
+                shard.index,
+                shard.total,
+                funnel.summary()
+            );
+            return;
+        }
+        error!(
+            "No URLs left to analyze: {}. Check the input and any --include, --exclude or --group filters, or pass --continue-on-empty-file to allow an empty batch.",
+            funnel.summary()
+        );
+        std::process::exit(ExitCode::NoUrls.code());
+    }
+
+    let hash_length = collision_free_hash_length(&urls, args.hash_length as usize);
 
-        // Check that all filenames start with prefix and end with .html
-        for filename in &filenames {
-            assert!(filename.starts_with("test_"));
-            assert!(filename.ends_with(".html"));
-            // Check that filename has expected length: prefix + underscore + 12 hex chars + .html
-            assert_eq!(filename.len(), "test_".len() + 12 + ".html".len());
+    // --- 3. Run Lighthouse for each URL ---
+    let chrome_flags = match chrome_flags(&args) {
+        Ok(flags) => flags,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(ExitCode::Usage.code());
         }
+    };
 
-        // All filenames should be unique due to different URLs producing different hashes
-        let unique_filenames: std::collections::HashSet<_> = filenames.iter().collect();
-        assert_eq!(unique_filenames.len(), filenames.len());
+    if args.dry_run {
+        info!("Dry run: no Lighthouse commands will be executed");
+    }
+    let options = AuditOptions {
+        lighthouse_bin,
+        report_prefix,
+        format: args.format,
+        form_factor: form_factors[0],
+        tag_form_factor: false,
+        only_categories: args.only_categories.clone(),
+        chrome_flags,
+        extra_headers,
+        throttling: args.throttling,
+        locale: args.locale.clone(),
+        user_agent: args.user_agent.clone(),
+        plugins: args.plugins.clone(),
+        runs: args.runs,
+        warmup: args.warmup,
+        keep_run: args.keep_run,
+        filename_style: args.filename_style,
+        hash_length,
+        compress: args.compress,
+        view: args.view,
+        keep_cache: args.keep_cache,
+        quiet: args.quiet,
+        retries: args.retries,
+        retry_budget: args
+            .max_retries_total
+            .map(|limit| Arc::new(RetryBudget::new(limit))),
+        cancel: CancelToken::default(),
+        timeout: args.timeout.map(Duration::from_secs),
+        resume: args.resume,
+        dry_run: args.dry_run,
+        logs: !args.no_logs,
+        budget_path: args.budget.clone(),
+        config_path: args.lh_config.clone(),
+        artifacts: if args.gather_only {
+            Some(ArtifactMode::Gather(output_dir.clone()))
+        } else {
+            args.audit_only
+                .as_ref()
+                .map(|run_dir| ArtifactMode::Audit(PathBuf::from(run_dir)))
+        },
+        chrome_path,
+        file_prefix: if args.flat {
+            format!("{}_", dir_name)
+        } else {
+            String::new()
+        },
+        report_name: None,
+    };
+    let config = BatchConfig {
+        name,
+        timestamp,
+        output_dir,
+        urls,
+        overrides,
+        labels,
+        groups,
+        options,
+        form_factors,
+        concurrency,
+        delay: Duration::from_millis(args.delay),
+        per_host_delay: Duration::from_millis(args.per_host_delay),
+        max_memory_percent: args.max_memory_percent,
+        max_duration: args.max_duration.map(Duration::from_secs),
+        fail_fast: args.fail_fast,
+        fail_on_warnings: args.fail_on_warnings,
+        check_redirects: args.check_redirects,
+        audit_final_url: args.audit_final_url,
+        preflight: args.preflight,
+        skip_unreachable: args.skip_unreachable,
+        lighthouse_version,
+        pdf: args.pdf,
+        db: args.db.as_ref().map(PathBuf::from),
+        metrics_file: args.metrics_file.as_ref().map(PathBuf::from),
+        junit: args.junit.as_ref().map(PathBuf::from),
+        markdown: args.markdown.as_ref().map(PathBuf::from),
+        lighthouse_config,
+        chrome_channel: args.chrome_channel,
+        chrome_version,
+        open_summary: args.open_summary,
+        slack_webhook,
+        s3_bucket: args.s3_bucket.clone(),
+        s3_prefix: args.s3_prefix.clone(),
+        baseline: args.baseline.clone(),
+        regression_threshold: args.regression_threshold,
+        thresholds: Thresholds {
+            performance: args.min_performance,
+            accessibility: args.min_accessibility,
+            best_practices: args.min_best_practices,
+            seo: args.min_seo,
+        },
+    };
 
-        // Test that same URLs produce same filenames (deterministic)
-        let same_url_filenames: Vec<String> = urls
+    // Ctrl-C stops the batch but still writes the summary for what finished
+    let cancel = config.options.cancel.clone();
+    let installed = ctrlc::set_handler(move || {
+        if cancel.cancel() {
+            warn!("Interrupted; waiting for audits in progress, then writing the summary...");
+        }
+    });
+    if let Err(e) = installed {
+        warn!("Could not install the Ctrl-C handler: {}", e);
+    }
+
+    let report = run_batch(&config);
+    if (args.archive || args.archive_only) && !args.dry_run {
+        match write_archive(&config.output_dir) {
+            Ok(archive) => {
+                info!("Wrote archive: {}", archive.display());
+                if args.archive_only {
+                    match fs::remove_dir_all(&config.output_dir) {
+                        Ok(()) => info!(
+                            "Removed '{}', keeping only the archive",
+                            config.output_dir.display()
+                        ),
+                        Err(e) => {
+                            warn!("Could not remove '{}': {}", config.output_dir.display(), e)
+                        }
+                    }
+                }
+            }
+            Err(e) => error!("Failed to archive '{}': {}", config.output_dir.display(), e),
+        }
+    }
+    if args.retry_failures.is_some() && !args.dry_run {
+        let passed = report
+            .results
             .iter()
-            .map(|url| url_to_filename(url, "test"))
-            .collect();
-        assert_eq!(filenames, same_url_filenames);
+            .filter(|result| result.status == UrlStatus::Success)
+            .count();
+        info!(
+            "{} of {} previously failing URL(s) now pass",
+            passed,
+            report.results.len()
+        );
+    }
+    if args.fail_on_budget && report.over_budget > 0 && !report.interrupted {
+        error!("Failing the run because URLs exceeded the budget (--fail-on-budget).");
+    }
+    let exit_code = report.exit_code(args.fail_on_budget);
+    if exit_code != ExitCode::Success {
+        std::process::exit(exit_code.code());
+    }
+}
+
+/// Routes log output to stderr: info and above by default, debug with `-v`, trace with
+/// `-vv`. Other crates only log warnings unless `RUST_LOG` says otherwise.
+fn init_logging(verbose: u8, quiet: bool) {
+    let level = match verbose {
+        _ if quiet => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Warn)
+        .filter_module(env!("CARGO_CRATE_NAME"), level)
+        // The more specific target wins, so --quiet keeps the progress and summary
+        .filter_module(SUMMARY_LOG_TARGET, level.max(LevelFilter::Info))
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            // Progress stays readable; everything else is tagged so it can be grepped
+            log::Level::Info => writeln!(buf, "{}", record.args()),
+            level => writeln!(buf, "{}: {}", level.as_str().to_lowercase(), record.args()),
+        })
+        .init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_config_parse() {
+        let config = FileConfig::parse(
+            r#"
+                name = "marketing-site"
+                reports_dir = "audits"
+                format = "both"
+                concurrency = 4
+                throttling = "4g"
+
+                [thresholds]
+                performance = 80
+                best_practices = 90
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.name.as_deref(), Some("marketing-site"));
+        assert_eq!(config.format, Some(OutputFormat::Both));
+        assert_eq!(config.throttling, Some(Throttling::FourG));
+        assert_eq!(config.thresholds.performance, Some(80));
+        assert_eq!(config.thresholds.best_practices, Some(90));
+        assert_eq!(config.thresholds.seo, None);
+
+        assert_eq!(FileConfig::parse("").unwrap(), FileConfig::default());
+        assert!(FileConfig::parse("concurency = 4").is_err());
+        assert_eq!(
+            FileConfig::parse("concurrency = \"auto\"")
+                .unwrap()
+                .concurrency,
+            Some(Concurrency::Auto)
+        );
+        assert!(FileConfig::parse("concurrency = \"lots\"").is_err());
+        assert!(FileConfig::parse("[thresholds]\nseo = 101").is_err());
+    }
+
+    #[test]
+    fn test_file_config_precedence() {
+        let config = FileConfig::parse(
+            r#"
+                name = "from-file"
+                reports_dir = "audits"
+                concurrency = 4
+                [thresholds]
+                seo = 70
+            "#,
+        )
+        .unwrap();
+        let matches = Args::command()
+            .try_get_matches_from(["batch_analyzer", "--name", "from-cli", "-c", "2"])
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        config.apply(&mut args, &matches);
+
+        // Flags on the command line win; the file replaces built-in defaults
+        assert_eq!(args.name.as_deref(), Some("from-cli"));
+        assert_eq!(args.concurrency, Concurrency::Fixed(2));
+        assert_eq!(args.reports_dir, "audits");
+        assert_eq!(args.format, OutputFormat::Html);
+        assert_eq!(args.min_seo, Some(70));
+    }
+
+    #[test]
+    fn test_chrome_flags_accept_hyphen_values() {
+        let args = Args::try_parse_from([
+            "batch_analyzer",
+            "--chrome-flags",
+            "--headless --disable-gpu",
+            "--extra-chrome-flags=--mute-audio",
+        ])
+        .expect("hyphenated values should parse");
+        assert_eq!(
+            args.chrome_flags.as_deref(),
+            Some("--headless --disable-gpu")
+        );
+        assert_eq!(args.extra_chrome_flags.as_deref(), Some("--mute-audio"));
+    }
+
+    #[test]
+    fn test_doctor_subcommand() {
+        let args = Args::try_parse_from(["batch_analyzer", "-r", "out", "doctor"]).unwrap();
+        assert_eq!(args.reports_dir, "out");
+        assert!(
+            matches!(args.command, Some(Commands::Doctor { url }) if url == "https://example.com")
+        );
+
+        let args = Args::try_parse_from(["batch_analyzer", "--name", "site"]).unwrap();
+        assert!(args.command.is_none());
+    }
+
+    #[test]
+    fn test_chrome_flags() {
+        let flags = |extra: &[&str]| {
+            let args = Args::try_parse_from(["batch_analyzer"].iter().chain(extra)).unwrap();
+            chrome_flags(&args)
+        };
+        assert_eq!(flags(&[]).unwrap(), DEFAULT_CHROME_FLAGS);
+        assert_eq!(
+            flags(&["--keep-cache", "--user-data-dir", "/tmp/profile"]).unwrap(),
+            ["--headless", "--no-sandbox", "--user-data-dir=/tmp/profile"]
+        );
+        assert_eq!(
+            flags(&[
+                "--chrome-flags=--headless --user-data-dir=/tmp/old --disable-cache",
+                "--keep-cache",
+                "--proxy",
+                "http://proxy.corp:3128",
+                "--user-data-dir",
+                "/tmp/new",
+            ])
+            .unwrap(),
+            [
+                "--headless",
+                "--proxy-server=http://proxy.corp:3128",
+                "--user-data-dir=/tmp/new"
+            ]
+        );
+        assert!(flags(&["--user-data-dir", "/tmp/profile", "--concurrency", "2"]).is_err());
+        assert!(flags(&["--proxy", "ftp://proxy.corp"]).is_err());
+    }
+
+    #[test]
+    fn test_only_categories_parsing() {
+        let args = Args::try_parse_from([
+            "batch_analyzer",
+            "--only-categories",
+            "performance,best-practices,seo",
+        ])
+        .expect("valid categories should parse");
+        assert_eq!(
+            args.only_categories,
+            vec![
+                Category::Performance,
+                Category::BestPractices,
+                Category::Seo
+            ]
+        );
+
+        let typo = Args::try_parse_from(["batch_analyzer", "--only-categories", "perfomance"]);
+        assert!(typo.is_err());
+    }
+
+    #[test]
+    fn test_create_unique_dir() {
+        let reports = env::temp_dir().join(format!("test_run_dirs_{}", std::process::id()));
+        fs::create_dir_all(&reports).unwrap();
+        let base = reports.join("site_20250101_120000");
+
+        // A folder left by a run in the same second is never reused
+        assert_eq!(create_unique_dir(&base).unwrap(), base);
+        assert_eq!(
+            create_unique_dir(&base).unwrap(),
+            reports.join("site_20250101_120000_1")
+        );
+        assert_eq!(
+            create_unique_dir(&base).unwrap(),
+            reports.join("site_20250101_120000_2")
+        );
+        fs::remove_dir_all(&reports).ok();
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        let time = Utc.with_ymd_and_hms(2025, 3, 9, 14, 5, 7).unwrap();
+        assert_eq!(
+            format_timestamp(&time, DEFAULT_TIMESTAMP_FORMAT).unwrap(),
+            "20250309_140507"
+        );
+        assert_eq!(
+            format_timestamp(&time, "%Y-%m-%dT%H%M%SZ").unwrap(),
+            "2025-03-09T140507Z"
+        );
 
-        // Clean up
-        fs::remove_file(&temp_file).expect("Failed to remove test file");
+        // Path separators, colons and unknown specifiers are rejected
+        assert!(format_timestamp(&time, "%Y/%m/%d").is_err());
+        assert!(format_timestamp(&time, "%H:%M").is_err());
+        assert!(format_timestamp(&time, "%Q").is_err());
+        assert!(format_timestamp(&time, "..").is_err());
     }
 }